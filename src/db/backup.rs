@@ -0,0 +1,152 @@
+use crate::error::Error;
+use rusqlite::backup::{Backup, Progress};
+use rusqlite::Connection;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Number of historical backups to retain. Older ones are pruned after each new backup.
+const MAX_BACKUPS: usize = 5;
+
+/// Snapshot the live database into a sibling `gossip.sqlite.bak.<unix_timestamp>` file
+/// using rusqlite's online backup API, which is safe to run against an open connection.
+pub fn backup_database(data_dir: &Path, src: &Connection) -> Result<PathBuf, Error> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| Error::from("System clock is set before the unix epoch"))?
+        .as_secs();
+
+    let mut backup_path = data_dir.to_path_buf();
+    backup_path.push(format!("gossip.sqlite.bak.{}", timestamp));
+
+    tracing::info!("Backing up database to {}", backup_path.display());
+
+    let mut dst = Connection::open(&backup_path)?;
+    let backup = Backup::new(&src, &mut dst)?;
+    backup.run_to_completion(100, Duration::from_millis(0), Some(log_progress))?;
+
+    tracing::info!("Database backup complete: {}", backup_path.display());
+
+    prune_old_backups(data_dir)?;
+
+    Ok(backup_path)
+}
+
+fn log_progress(progress: Progress) {
+    tracing::debug!(
+        "Database backup in progress: {} of {} pages remaining",
+        progress.remaining,
+        progress.pagecount
+    );
+}
+
+/// Delete old backup files, keeping only the `MAX_BACKUPS` most recent ones.
+fn prune_old_backups(data_dir: &Path) -> Result<(), Error> {
+    let mut backups: Vec<PathBuf> = fs::read_dir(data_dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .map(|name| name.starts_with("gossip.sqlite.bak."))
+                .unwrap_or(false)
+        })
+        .collect();
+
+    // Timestamps are suffixed in increasing order, so a lexical sort is a chronological sort.
+    backups.sort();
+
+    while backups.len() > MAX_BACKUPS {
+        let oldest = backups.remove(0);
+        tracing::info!("Removing old database backup {}", oldest.display());
+        fs::remove_file(&oldest)?;
+    }
+
+    Ok(())
+}
+
+/// Restore the live `gossip.sqlite` from a backup file created by [`backup_database`].
+pub fn restore_from_backup(backup_path: &Path) -> Result<(), Error> {
+    let data_dir = backup_path
+        .parent()
+        .ok_or("Backup path has no parent directory")?;
+
+    let mut db_path = data_dir.to_path_buf();
+    db_path.push("gossip.sqlite");
+
+    tracing::info!(
+        "Restoring database from backup {} to {}",
+        backup_path.display(),
+        db_path.display()
+    );
+
+    let src = Connection::open(backup_path)?;
+    let mut dst = Connection::open(&db_path)?;
+    let backup = Backup::new(&src, &mut dst)?;
+    backup.run_to_completion(100, Duration::from_millis(0), None)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(label: &str) -> PathBuf {
+        let mut path = std::env::temp_dir();
+        path.push(format!(
+            "gossip-backup-test-{}-{}-{}",
+            label,
+            std::process::id(),
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos()
+        ));
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn prune_old_backups_keeps_only_max_backups() {
+        let dir = temp_dir("prune");
+
+        for i in 0..(MAX_BACKUPS + 3) {
+            let mut path = dir.clone();
+            path.push(format!("gossip.sqlite.bak.{:010}", i));
+            fs::write(&path, b"x").unwrap();
+        }
+
+        prune_old_backups(&dir).unwrap();
+
+        let remaining = fs::read_dir(&dir).unwrap().count();
+        assert_eq!(remaining, MAX_BACKUPS);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn restore_from_backup_round_trips() {
+        let dir = temp_dir("roundtrip");
+
+        let mut db_path = dir.clone();
+        db_path.push("gossip.sqlite");
+
+        let src = Connection::open(&db_path).unwrap();
+        src.execute_batch("CREATE TABLE t (v INTEGER); INSERT INTO t VALUES (42);")
+            .unwrap();
+
+        let backup_path = backup_database(&dir, &src).unwrap();
+        drop(src);
+
+        // Delete the live database so the restored value can only come from the backup.
+        fs::remove_file(&db_path).unwrap();
+
+        restore_from_backup(&backup_path).unwrap();
+
+        let restored = Connection::open(&db_path).unwrap();
+        let v: i64 = restored
+            .query_row("SELECT v FROM t", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(v, 42);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,296 @@
+use crate::error::Error;
+use fallible_iterator::FallibleIterator;
+use r2d2::PooledConnection;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::Transaction;
+
+/// A single schema migration: either an embedded SQL batch, or a one-off Rust data
+/// transformation for changes SQL can't express cleanly.
+pub enum MigrationStep {
+    Sql(&'static str),
+    Rust(fn(&Transaction) -> Result<(), Error>),
+}
+
+/// Schema migrations in order. Index 0 takes a database from version 0 to version 1,
+/// and so on. `CURRENT_VERSION` is always `MIGRATIONS.len()`.
+pub const MIGRATIONS: &[MigrationStep] = &[
+    MigrationStep::Sql(include_str!("sql/schema1.sql")),
+    MigrationStep::Sql(include_str!("sql/schema2.sql")),
+    MigrationStep::Sql(include_str!("sql/schema3.sql")),
+    MigrationStep::Sql(include_str!("sql/schema4.sql")),
+    MigrationStep::Sql(include_str!("sql/schema5.sql")),
+    MigrationStep::Sql(include_str!("sql/schema6.sql")),
+    MigrationStep::Sql(include_str!("sql/schema7.sql")),
+    MigrationStep::Sql(include_str!("sql/schema8.sql")),
+    MigrationStep::Sql(include_str!("sql/schema9.sql")),
+    MigrationStep::Sql(include_str!("sql/schema10.sql")),
+    MigrationStep::Sql(include_str!("sql/schema11.sql")),
+    MigrationStep::Sql(include_str!("sql/schema12.sql")),
+    MigrationStep::Sql(include_str!("sql/schema13.sql")),
+    MigrationStep::Sql(include_str!("sql/schema14.sql")),
+    MigrationStep::Sql(include_str!("sql/schema15.sql")),
+    MigrationStep::Sql(include_str!("sql/schema16.sql")),
+    MigrationStep::Sql(include_str!("sql/schema17.sql")),
+    MigrationStep::Sql(include_str!("sql/schema18.sql")),
+    MigrationStep::Sql(include_str!("sql/schema19.sql")),
+    MigrationStep::Sql(include_str!("sql/schema20.sql")),
+    MigrationStep::Sql(include_str!("sql/schema21.sql")),
+    MigrationStep::Sql(include_str!("sql/schema22.sql")),
+    MigrationStep::Sql(include_str!("sql/schema23.sql")),
+    MigrationStep::Sql(include_str!("sql/schema24.sql")),
+    MigrationStep::Sql(include_str!("sql/schema25.sql")),
+    MigrationStep::Sql(include_str!("sql/schema26.sql")),
+    MigrationStep::Sql(include_str!("sql/schema27.sql")),
+    MigrationStep::Sql(include_str!("sql/schema28.sql")),
+    MigrationStep::Sql(include_str!("sql/schema29.sql")),
+    MigrationStep::Sql(include_str!("sql/schema30.sql")),
+    MigrationStep::Sql(include_str!("sql/schema31.sql")),
+    MigrationStep::Rust(normalize_urls_step),
+];
+
+pub const CURRENT_VERSION: usize = MIGRATIONS.len();
+
+/// Drives a sqlite database through first-run initialization and version-gated
+/// migrations.
+pub trait SchemaMigrator {
+    /// Pragmas that must run outside any transaction.
+    fn prepare(&self) -> Result<(), Error>;
+
+    /// Initialize a brand-new database at version 0.
+    fn init(&mut self) -> Result<(), Error>;
+
+    /// Walk from `from_version` to `CURRENT_VERSION`, one step per version gap.
+    fn upgrade_from(&mut self, from_version: usize) -> Result<(), Error>;
+}
+
+pub struct SqliteMigrator {
+    pub conn: PooledConnection<SqliteConnectionManager>,
+}
+
+impl SchemaMigrator for SqliteMigrator {
+    fn prepare(&self) -> Result<(), Error> {
+        // Pragmas are already applied pool-wide by GossipConnectionCustomizer.
+        Ok(())
+    }
+
+    fn init(&mut self) -> Result<(), Error> {
+        self.upgrade_from(0)
+    }
+
+    fn upgrade_from(&mut self, from_version: usize) -> Result<(), Error> {
+        self.run_steps(MIGRATIONS, from_version)
+    }
+}
+
+impl SqliteMigrator {
+    /// Walk `steps` from `from_version` to `steps.len()`, one step per version gap.
+    /// Split out from `upgrade_from` so tests can exercise the rollback behavior
+    /// against a small fixture instead of the full `MIGRATIONS` list.
+    fn run_steps(&mut self, steps: &[MigrationStep], from_version: usize) -> Result<(), Error> {
+        let target = steps.len();
+
+        if from_version > target {
+            panic!(
+                "Database version {} is newer than this binary which expects version {}.",
+                from_version, target
+            );
+        }
+
+        let mut version = from_version;
+
+        while version < target {
+            let next_version = version + 1;
+            tracing::info!("Upgrading database to version {}", next_version);
+
+            // Run the migration step and the version bump in one transaction so a crash
+            // mid-migration can never leave schema_version pointing at a step that
+            // wasn't fully applied.
+            let tx = self.conn.transaction()?;
+
+            match &steps[next_version - 1] {
+                MigrationStep::Sql(sql) => tx.execute_batch(sql).map_err(|e| {
+                    Error::from(format!(
+                        "Migration to schema version {} failed, rolled back: {}",
+                        next_version, e
+                    ))
+                })?,
+                MigrationStep::Rust(step) => step(&tx).map_err(|e| {
+                    Error::from(format!(
+                        "Migration to schema version {} failed, rolled back: {}",
+                        next_version, e
+                    ))
+                })?,
+            }
+
+            if next_version < 24 {
+                // 24 is when we switched to local_settings
+                tx.execute(
+                    "UPDATE settings SET value=? WHERE key='version'",
+                    (next_version,),
+                )?;
+            } else {
+                tx.execute(
+                    "UPDATE local_settings SET schema_version=?",
+                    (next_version,),
+                )?;
+            }
+
+            tx.commit()?;
+
+            version = next_version;
+        }
+
+        tracing::info!("Database is at version {}", version);
+
+        Ok(())
+    }
+}
+
+/// Normalize every stored relay URL to its canonical form.
+fn normalize_urls_step(tx: &Transaction) -> Result<(), Error> {
+    tracing::info!("Normalizing Database URLs (this will take some time)");
+
+    // Defer FK checks to commit time, since rewriting relay.url and its referencing
+    // rows can transiently point a child row at a url that doesn't exist yet.
+    tx.pragma_update(None, "defer_foreign_keys", "ON")?;
+
+    // relay.url
+    let sql = "SELECT url FROM relay";
+    let mut stmt = tx.prepare(sql)?;
+    let rows = stmt.query([])?;
+    let all_rows: Vec<String> = rows.map(|row| row.get(0)).collect()?;
+    for urlkey in all_rows.iter() {
+        match nostr_types::RelayUrl::try_from_str(urlkey) {
+            Ok(url) => {
+                let urlstr = url.as_str().to_owned();
+                // Update if not equal
+                if *urlkey != urlstr {
+                    // this one is too verbose
+                    // tracing::debug!("Updating non-canonical URL from {} to {}", urlkey, urlstr);
+                    let usql = "UPDATE relay SET url=? WHERE url=?";
+                    let mut stmt = tx.prepare(usql)?;
+                    if let Err(e) = stmt.execute((&urlstr, urlkey)) {
+                        if let rusqlite::Error::SqliteFailure(_, Some(ref s)) = e {
+                            if s.contains("constraint failed") {
+                                // Delete this row instead, there is some other row that is already
+                                // what we are trying to turn this row into
+                                let dsql = "DELETE FROM relay WHERE url=?";
+                                let mut stmt = tx.prepare(dsql)?;
+                                stmt.execute((&urlkey,))?;
+                            }
+                        } else {
+                            return Err(e.into());
+                        }
+                    }
+
+                    let usql = "UPDATE person_relay SET relay=? WHERE relay=?";
+                    let mut stmt = tx.prepare(usql)?;
+                    stmt.execute((&urlstr, urlkey))?;
+
+                    let usql = "UPDATE event_relay SET relay=? WHERE relay=?";
+                    let mut stmt = tx.prepare(usql)?;
+                    stmt.execute((&urlstr, urlkey))?;
+                }
+            }
+            Err(_) => {
+                // Delete if did not parse properly
+                tracing::debug!("Deleting invalid relay url {}", urlkey);
+
+                let dsql = "DELETE FROM relay WHERE url=?";
+                let mut stmt = tx.prepare(dsql)?;
+                stmt.execute((urlkey,))?;
+
+                let dsql = "DELETE FROM person_relay WHERE relay=?";
+                let mut stmt = tx.prepare(dsql)?;
+                stmt.execute((urlkey,))?;
+
+                let dsql = "DELETE FROM event_relay WHERE relay=?";
+                let mut stmt = tx.prepare(dsql)?;
+                stmt.execute((urlkey,))?;
+            }
+        };
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use r2d2::Pool;
+    use std::path::PathBuf;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn temp_db_path(label: &str) -> PathBuf {
+        let mut path = std::env::temp_dir();
+        path.push(format!(
+            "gossip-migrations-test-{}-{}-{}.sqlite",
+            label,
+            std::process::id(),
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos()
+        ));
+        path
+    }
+
+    fn test_migrator(path: &std::path::Path) -> SqliteMigrator {
+        let pool = Pool::new(SqliteConnectionManager::file(path)).unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE settings (key TEXT PRIMARY KEY, value TEXT); \
+             INSERT INTO settings VALUES ('version', '0');",
+        )
+        .unwrap();
+        SqliteMigrator { conn }
+    }
+
+    fn version(migrator: &SqliteMigrator) -> String {
+        migrator
+            .conn
+            .query_row("SELECT value FROM settings WHERE key='version'", [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn successful_steps_advance_schema_version() {
+        let path = temp_db_path("ok");
+        let mut migrator = test_migrator(&path);
+
+        let steps = [
+            MigrationStep::Sql("CREATE TABLE a (x INTEGER);"),
+            MigrationStep::Sql("CREATE TABLE b (x INTEGER);"),
+        ];
+
+        migrator.run_steps(&steps, 0).unwrap();
+
+        assert_eq!(version(&migrator), "2");
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn failing_step_rolls_back_its_ddl_and_leaves_version_unchanged() {
+        let path = temp_db_path("fail");
+        let mut migrator = test_migrator(&path);
+
+        let steps = [
+            MigrationStep::Sql("CREATE TABLE a (x INTEGER);"),
+            // Creates table b, then fails: both must roll back together.
+            MigrationStep::Sql("CREATE TABLE b (x INTEGER); INSERT INTO no_such_table VALUES (1);"),
+        ];
+
+        assert!(migrator.run_steps(&steps, 0).is_err());
+
+        assert_eq!(version(&migrator), "1", "step 1's commit should stick");
+
+        let table_b: Result<String, _> = migrator.conn.query_row(
+            "SELECT name FROM sqlite_master WHERE type='table' AND name='b'",
+            [],
+            |row| row.get(0),
+        );
+        assert!(table_b.is_err(), "step 2's DDL must not have been applied");
+
+        std::fs::remove_file(&path).ok();
+    }
+}